@@ -0,0 +1,184 @@
+use core::arch::asm;
+use core::fmt;
+use rustc_demangle::demangle;
+use crate::sync::IrqOnceCell;
+use crate::memory::{is_accessible, is_kernel_stack};
+
+/// Stop walking after this many frames, in case the frame pointer chain is corrupt
+const MAX_FRAMES: usize = 32;
+
+const SHT_SYMTAB: u32 = 2;
+#[cfg(feature = "lock-validator")]
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+/// The kernel's symbol table, read from the ELF file the bootloader loaded us from
+struct KernelSymbols {
+    symbols: &'static [u8],
+    strings: &'static [u8],
+    /// Added to symbol values to get where they were actually loaded
+    image_offset: u64,
+}
+
+static SYMBOLS: IrqOnceCell<KernelSymbols> = IrqOnceCell::new();
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+impl KernelSymbols {
+    /// Finds `.symtab` and its string table in the section headers of `kernel`
+    fn parse(kernel: &'static [u8], image_offset: u64) -> Option<Self> {
+        let section_headers = read_u64(kernel, 0x28)? as usize;
+        let section_header_size = read_u16(kernel, 0x3a)? as usize;
+        let section_count = read_u16(kernel, 0x3c)? as usize;
+
+        let section = |index: usize| {
+            let base = section_headers.checked_add(index.checked_mul(section_header_size)?)?;
+            let header = kernel.get(base..base.checked_add(section_header_size)?)?;
+
+            let kind = read_u32(header, 4)?;
+            let offset = read_u64(header, 24)? as usize;
+            let size = read_u64(header, 32)? as usize;
+            let link = read_u32(header, 40)?;
+
+            Some((kind, kernel.get(offset..offset.checked_add(size)?)?, link))
+        };
+
+        let (_, symbols, strings_index) = (0..section_count)
+            .filter_map(section)
+            .find(|(kind, _, _)| *kind == SHT_SYMTAB)?;
+        let (_, strings, _) = section(strings_index as usize)?;
+
+        Some(KernelSymbols {
+            symbols,
+            strings,
+            image_offset,
+        })
+    }
+
+    /// Returns the symbol of type `kind` containing `addr`, and how far into it `addr` is
+    fn lookup(&self, addr: u64, kind: u8) -> Option<(&'static str, u64)> {
+        self.symbols.chunks_exact(SYMBOL_SIZE).find_map(|symbol| {
+            let name = read_u32(symbol, 0)? as usize;
+            let info = *symbol.get(4)?;
+            let start = read_u64(symbol, 8)?.checked_add(self.image_offset)?;
+            let size = read_u64(symbol, 16)?;
+
+            if info & 0xf != kind || addr < start || addr - start >= size {
+                return None;
+            }
+
+            let name = self.strings.get(name..)?;
+            let name = &name[..name.iter().position(|byte| *byte == 0)?];
+
+            Some((core::str::from_utf8(name).ok()?, addr - start))
+        })
+    }
+}
+
+/// Loads symbols from the kernel ELF at `kernel`, which was loaded at `image_offset`.
+/// Without this (or if the kernel was stripped) backtraces only have addresses.
+///
+/// # Safety
+/// `kernel` must point to the kernel's ELF file, `len` bytes long, and stay mapped
+pub unsafe fn init(kernel: *const u8, len: usize, image_offset: u64) {
+    let kernel = unsafe { core::slice::from_raw_parts(kernel, len) };
+
+    if let Some(symbols) = KernelSymbols::parse(kernel, image_offset) {
+        SYMBOLS.call_once(|| symbols);
+    }
+}
+
+/// Return addresses of the calls leading up to where it was captured, so a stack can be kept and
+/// printed later. Requires the kernel to be built with frame pointers.
+#[derive(Clone)]
+pub struct Trace {
+    addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Trace {
+    pub const fn empty() -> Self {
+        Trace {
+            addresses: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// Walks the frame pointer chain from the caller. Always inlined so the caller's own frame is
+    /// where the walk starts.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut frame_pointer: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack)) };
+
+        let mut trace = Trace::empty();
+
+        while trace.len < MAX_FRAMES {
+            // Each frame starts with the caller's frame pointer followed by the return address.
+            // Checking the stack bounds first avoids the mapper lock, which the caller may hold.
+            if frame_pointer % 8 != 0 || !(is_kernel_stack(frame_pointer, 16) || is_accessible(frame_pointer, 16, false)) {
+                break;
+            }
+
+            let (next, return_address) = unsafe {
+                let frame = frame_pointer as *const u64;
+                (*frame, *frame.add(1))
+            };
+
+            if return_address == 0 {
+                break;
+            }
+
+            trace.addresses[trace.len] = return_address;
+            trace.len += 1;
+
+            // Stacks grow down, so callers' frames are always higher up
+            if next <= frame_pointer {
+                break;
+            }
+            frame_pointer = next;
+        }
+
+        trace
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, return_address) in self.addresses[..self.len].iter().enumerate() {
+            // The return address may be just past the end of the calling function, so look up the
+            // call
+            match SYMBOLS.get().and_then(|symbols| symbols.lookup(return_address - 1, STT_FUNC)) {
+                Some((name, offset)) => writeln!(f, "{:>3}: {:#018x} {:#}+{:#x}", index, return_address, demangle(name), offset + 1)?,
+                None => writeln!(f, "{:>3}: {:#018x}", index, return_address)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the backtrace of the caller, with the function each return address is in
+#[inline(always)]
+pub fn print(f: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(f, "Backtrace:")?;
+    write!(f, "{}", Trace::capture())
+}
+
+/// Name of the static variable at `addr`, if there are symbols for it
+#[cfg(feature = "lock-validator")]
+pub fn object_name(addr: u64) -> Option<impl fmt::Display> {
+    let (name, offset) = SYMBOLS.get()?.lookup(addr, STT_OBJECT)?;
+    (offset == 0).then(|| demangle(name))
+}
@@ -1,30 +1,133 @@
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt;
-use bootloader_api::info::{FrameBuffer, FrameBufferInfo};
+use crate::sync::IrqSpinlock;
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
 use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
 use x86_64::instructions::port::Port;
+use log::Level;
+use crate::klog::Sink;
 
 const SIZE: RasterHeight = RasterHeight::Size32;
 
+/// The 16 ANSI colours as RGB, normal then bright
+const PALETTE: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [170, 0, 0],
+    [0, 170, 0],
+    [170, 85, 0],
+    [0, 0, 170],
+    [170, 0, 170],
+    [0, 170, 170],
+    [170, 170, 170],
+    [85, 85, 85],
+    [255, 85, 85],
+    [85, 255, 85],
+    [255, 255, 85],
+    [85, 85, 255],
+    [255, 85, 255],
+    [85, 255, 255],
+    [255, 255, 255],
+];
+const DEFAULT_FOREGROUND: u8 = 15;
+const DEFAULT_BACKGROUND: u8 = 0;
+
+/// Screens of history kept above the visible screen by default
+pub const SCROLLBACK_SCREENS: usize = 4;
+
+/// Maximum number of parameters kept for a CSI sequence, extra parameters are ignored
+const MAX_CSI_PARAMS: usize = 8;
+
 /// Internal struct used by console to store framebuffer
 struct Framebuffer {
     framebuffer_info: FrameBufferInfo,
     raw_framebuffer: &'static mut [u8],
 }
 
+impl Framebuffer {
+    fn write_pixel(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let info = self.framebuffer_info;
+        let base = (y * info.stride + x) * info.bytes_per_pixel;
+        let pixel = &mut self.raw_framebuffer[base..base + info.bytes_per_pixel];
+
+        match info.pixel_format {
+            PixelFormat::Bgr => pixel[..3].copy_from_slice(&[b, g, r]),
+            PixelFormat::U8 => pixel[0] = ((r as u16 * 77 + g as u16 * 150 + b as u16 * 29) >> 8) as u8,
+            _ => pixel[..3].copy_from_slice(&[r, g, b]),
+        }
+    }
+}
+
+/// Colours set by SGR escape sequences
+#[derive(Clone, Copy)]
+struct Attributes {
+    foreground: u8,
+    background: u8,
+    bold: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        foreground: DEFAULT_FOREGROUND,
+        background: DEFAULT_BACKGROUND,
+        bold: false,
+    };
+
+    fn colours(&self) -> ([u8; 3], [u8; 3]) {
+        // Bold brightens the normal colours, like the Linux console
+        let foreground = if self.bold && self.foreground < 8 {
+            self.foreground + 8
+        } else {
+            self.foreground
+        };
+
+        (PALETTE[foreground as usize], PALETTE[self.background as usize])
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    character: u8,
+    attributes: Attributes,
+}
+
+#[derive(Clone, Copy)]
+enum EscapeState {
+    Normal,
+    /// Received ESC
+    Escape,
+    /// Inside `ESC [`, collecting parameters until the final byte
+    Csi {
+        params: [u16; MAX_CSI_PARAMS],
+        count: usize,
+        private: bool,
+    },
+}
+
+/// The framebuffer console, once it has been set up
+pub static CONSOLE: IrqSpinlock<Option<Console>> = IrqSpinlock::new(None);
+
 pub struct Console {
-    characters: Vec<u8>,
+    characters: Vec<Cell>, // Ring of the visible screen plus scrollback history
     framebuffer: Framebuffer,
     row: usize,
     col: usize,
     rows: usize,
     cols: usize,
-    offset: usize
+    offset: usize,
+    history: usize, // Rows of history available above the screen
+    scrollback: usize, // Rows the view is currently scrolled back by
+    attributes: Attributes,
+    escape: EscapeState,
 }
 
 impl Console {
     pub fn new(framebuffer: &'static mut FrameBuffer) -> Self {
+        Self::with_scrollback(framebuffer, SCROLLBACK_SCREENS)
+    }
+
+    /// Creates a console keeping `screens` screens of history for scrolling back through
+    pub fn with_scrollback(framebuffer: &'static mut FrameBuffer, screens: usize) -> Self {
         let framebuffer = Framebuffer {
             framebuffer_info: framebuffer.info().clone(),
             raw_framebuffer: framebuffer.buffer_mut(),
@@ -35,21 +138,35 @@ impl Console {
             rows,
             cols,
             offset: 0,
-            characters: vec![b' '; rows * cols],
+            history: 0,
+            scrollback: 0,
+            characters: vec![Self::blank(Attributes::DEFAULT); rows * cols * (screens + 1)],
             framebuffer,
             row: 0,
             col: 0,
+            attributes: Attributes::DEFAULT,
+            escape: EscapeState::Normal,
         };
         console.full_redraw();
         console
     }
 
-    fn char_mut(&mut self, row: usize, col: usize) -> &mut u8 {
-        &mut self.characters[(row * self.cols + col + self.offset) % (self.rows * self.cols)]
+    fn blank(attributes: Attributes) -> Cell {
+        Cell {
+            character: b' ',
+            attributes,
+        }
     }
 
-    fn char_ref(&self, row: usize, col: usize) -> &u8 {
-        &self.characters[(row * self.cols + col + self.offset) % (self.rows * self.cols)]
+    fn char_mut(&mut self, row: usize, col: usize) -> &mut Cell {
+        let len = self.characters.len();
+        &mut self.characters[(row * self.cols + col + self.offset) % len]
+    }
+
+    /// The cell shown at (`row`, `col`), which is from the history if scrolled back
+    fn displayed_char(&self, row: usize, col: usize) -> &Cell {
+        let len = self.characters.len();
+        &self.characters[(row * self.cols + col + self.offset + len - self.scrollback * self.cols) % len]
     }
 
     pub fn read(&mut self, _buf: &[u8]) -> usize {
@@ -58,10 +175,11 @@ impl Console {
 
     fn newline(&mut self) {
         if self.row >= (self.rows - 1) {
-            self.offset = (self.offset + self.cols) % (self.rows * self.cols); // Scroll down
+            self.offset = (self.offset + self.cols) % self.characters.len(); // Scroll down
+            self.history = (self.history + 1).min(self.characters.len() / self.cols - self.rows);
             // Clear last row
             for x in 0..self.cols {
-                *self.char_mut(self.rows - 1, x) = b' ';
+                *self.char_mut(self.rows - 1, x) = Self::blank(self.attributes);
             }
             self.full_redraw();
         } else {
@@ -92,44 +210,217 @@ impl Console {
         let x = col * character_width;
         let y = SIZE.val() * row;
 
-        let raster = get_raster(*self.char_ref(row, col) as char, FontWeight::Regular, SIZE)
+        let cell = *self.displayed_char(row, col);
+        let (foreground, background) = cell.attributes.colours();
+
+        let raster = get_raster(cell.character as char, FontWeight::Regular, SIZE)
+            .or_else(|| get_raster('?', FontWeight::Regular, SIZE))
             .unwrap()
             .raster();
 
         for (row_i, row) in raster.iter().enumerate() {
             for (col_i, pixel) in row.iter().enumerate() {
-                let info = self.framebuffer.framebuffer_info;
-                let x = x + col_i;
-                let y = y + row_i;
-                let base = (y * info.stride + x) * info.bytes_per_pixel;
-                self.framebuffer.raw_framebuffer[base] = *pixel;
-                self.framebuffer.raw_framebuffer[base + 1] = *pixel;
-                self.framebuffer.raw_framebuffer[base + 2] = *pixel;
+                // The raster is an intensity, so blend between the background and foreground
+                let colour = core::array::from_fn(|channel| {
+                    ((background[channel] as u16 * (255 - *pixel as u16)
+                        + foreground[channel] as u16 * *pixel as u16)
+                        / 255) as u8
+                });
+
+                self.framebuffer.write_pixel(x + col_i, y + row_i, colour);
             }
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> usize {
-        for byte in buf {
-            match byte {
-                b'\x08' => {
+    /// Scrolls the view back through the history by up to `lines` rows
+    pub fn scroll_up(&mut self, lines: usize) {
+        let scrollback = (self.scrollback + lines).min(self.history);
+
+        if scrollback != self.scrollback {
+            self.scrollback = scrollback;
+            self.full_redraw();
+        }
+    }
+
+    /// Scrolls the view forward towards the live screen by up to `lines` rows
+    pub fn scroll_down(&mut self, lines: usize) {
+        let scrollback = self.scrollback.saturating_sub(lines);
+
+        if scrollback != self.scrollback {
+            self.scrollback = scrollback;
+            self.full_redraw();
+        }
+    }
+
+    /// For Shift+PageUp
+    #[allow(dead_code, reason = "for the keyboard driver, which doesn't exist yet")]
+    pub fn page_up(&mut self) {
+        self.scroll_up(self.rows);
+    }
+
+    /// For Shift+PageDown
+    #[allow(dead_code, reason = "for the keyboard driver, which doesn't exist yet")]
+    pub fn page_down(&mut self) {
+        self.scroll_down(self.rows);
+    }
+
+    /// Blanks every cell from `start` up to but not including `end`, both as (row, col)
+    fn erase(&mut self, start: (usize, usize), end: (usize, usize)) {
+        for position in (start.0 * self.cols + start.1)..(end.0 * self.cols + end.1) {
+            let (row, col) = (position / self.cols, position % self.cols);
+
+            *self.char_mut(row, col) = Self::blank(self.attributes);
+            self.update_character(row, col);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\x1b' => {
+                self.escape = EscapeState::Escape;
+            }
+            b'\x08' => {
+                if self.col > 0 {
                     self.col -= 1;
-                    *self.char_mut(self.row, self.col) = b' ';
+                    *self.char_mut(self.row, self.col) = Self::blank(self.attributes);
                     self.update_character(self.row, self.col);
                 }
-                b'\n' => {
-                    self.newline();
+            }
+            b'\n' => {
+                self.newline();
+            }
+            b'\r' => {
+                self.col = 0;
+            }
+            _ => {
+                *self.char_mut(self.row, self.col) = Cell {
+                    character: byte,
+                    attributes: self.attributes,
+                };
+                self.update_character(self.row, self.col);
+
+                if self.col == self.cols - 1 {
+                    self.newline()
+                } else {
+                    self.col += 1;
                 }
-                _ => {
-                    *self.char_mut(self.row, self.col) = *byte;
-                    self.update_character(self.row, self.col);
+            }
+        }
+    }
+
+    fn csi_byte(&mut self, byte: u8, mut params: [u16; MAX_CSI_PARAMS], mut count: usize, mut private: bool) {
+        match byte {
+            b'0'..=b'9' => {
+                if count < MAX_CSI_PARAMS {
+                    params[count] = params[count].saturating_mul(10).saturating_add((byte - b'0') as u16);
+                }
+            }
+            b';' => {
+                count += 1;
+            }
+            b'?' | b'<' | b'=' | b'>' => {
+                private = true;
+            }
+            0x40..=0x7e => {
+                self.escape = EscapeState::Normal;
+
+                // Private sequences (e.g. cursor visibility) aren't supported
+                if !private {
+                    let count = (count + 1).min(MAX_CSI_PARAMS);
+                    self.csi_dispatch(byte, &params[..count]);
+                }
+                return;
+            }
+            _ => {
+                // Malformed sequence, drop it
+                self.escape = EscapeState::Normal;
+                return;
+            }
+        }
+
+        self.escape = EscapeState::Csi {
+            params,
+            count,
+            private,
+        };
+    }
+
+    fn csi_dispatch(&mut self, command: u8, params: &[u16]) {
+        // Omitted or zero counts mean 1
+        let n = params[0].max(1) as usize;
+
+        match command {
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = (self.row + n).min(self.rows - 1),
+            b'C' => self.col = (self.col + n).min(self.cols - 1),
+            b'D' => self.col = self.col.saturating_sub(n),
+            b'G' => self.col = (n - 1).min(self.cols - 1),
+            b'H' | b'f' => {
+                let col = params.get(1).copied().unwrap_or(0).max(1) as usize;
+                self.row = (n - 1).min(self.rows - 1);
+                self.col = (col - 1).min(self.cols - 1);
+            }
+            b'J' => match params[0] {
+                0 => self.erase((self.row, self.col), (self.rows, 0)),
+                1 => self.erase((0, 0), (self.row, self.col + 1)),
+                _ => self.erase((0, 0), (self.rows, 0)),
+            },
+            b'K' => match params[0] {
+                0 => self.erase((self.row, self.col), (self.row + 1, 0)),
+                1 => self.erase((self.row, 0), (self.row, self.col + 1)),
+                _ => self.erase((self.row, 0), (self.row + 1, 0)),
+            },
+            b'm' => self.select_graphic_rendition(params),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        for param in params {
+            match *param {
+                0 => self.attributes = Attributes::DEFAULT,
+                1 => self.attributes.bold = true,
+                22 => self.attributes.bold = false,
+                30..=37 => self.attributes.foreground = (param - 30) as u8,
+                39 => self.attributes.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.attributes.background = (param - 40) as u8,
+                49 => self.attributes.background = DEFAULT_BACKGROUND,
+                90..=97 => self.attributes.foreground = (param - 90 + 8) as u8,
+                100..=107 => self.attributes.background = (param - 100 + 8) as u8,
+                // 256 colour and truecolour arguments would be misread as SGR codes, so stop here
+                38 | 48 => break,
+                _ => {}
+            }
+        }
+    }
+
+    /// Writes to the console, interpreting backspace, carriage return and newline, as well as
+    /// ANSI CSI sequences for cursor movement (`A`-`D`, `G`, `H`), erasing (`J`, `K`) and
+    /// 16 colour SGR attributes (`m`).
+    pub fn write(&mut self, buf: &[u8]) -> usize {
+        // New output jumps back to the live screen
+        self.scroll_down(self.scrollback);
 
-                    if self.col == self.cols - 1 {
-                        self.newline()
-                    } else {
-                        self.col += 1;
-                    }
+        for byte in buf {
+            match self.escape {
+                EscapeState::Normal => self.write_byte(*byte),
+                EscapeState::Escape if *byte == b'[' => {
+                    self.escape = EscapeState::Csi {
+                        params: [0; MAX_CSI_PARAMS],
+                        count: 0,
+                        private: false,
+                    };
+                }
+                EscapeState::Escape => {
+                    // Not a CSI sequence, so treat the byte as ordinary output
+                    self.escape = EscapeState::Normal;
+                    self.write_byte(*byte);
                 }
+                EscapeState::Csi {
+                    params,
+                    count,
+                    private,
+                } => self.csi_byte(*byte, params, count, private),
             }
         }
 
@@ -144,6 +435,27 @@ impl fmt::Write for Console {
     }
 }
 
+/// Sends kernel log messages to the framebuffer console, highlighting warnings and errors
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write(&self, level: Level, line: &str) {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            let colour: &[u8] = match level {
+                Level::Warn => b"\x1b[33m",
+                Level::Error => b"\x1b[31m",
+                _ => b"",
+            };
+
+            console.write(colour);
+            console.write(line.as_bytes());
+            if !colour.is_empty() {
+                console.write(b"\x1b[0m");
+            }
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! boot_print {
     ($console:expr, $($arg:tt)*) => (<Console as core::fmt::Write>::write_fmt($console, format_args!($($arg)*)).unwrap(););
@@ -185,3 +497,42 @@ macro_rules! debug_println {
         let _ = $crate::debug_print!("{}\n", format_args!($($arg)*));
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn csi_moves_cursor_and_sets_colours() {
+        let mut console = CONSOLE.lock();
+        let console = console.as_mut().unwrap();
+
+        console.write(b"\x1b[2J\x1b[H\x1b[31mab\x1b[0m");
+        assert_eq!((console.row, console.col), (0, 2));
+        assert_eq!(console.char_mut(0, 0).character, b'a');
+        assert_eq!(console.char_mut(0, 1).attributes.foreground, 1);
+        assert_eq!(console.attributes.foreground, DEFAULT_FOREGROUND);
+
+        console.write(b"\x1b[3;5H");
+        assert_eq!((console.row, console.col), (2, 4));
+
+        console.write(b"\x1b[2J\x1b[H");
+    }
+
+    #[test_case]
+    fn scrollback_keeps_history() {
+        let mut console = CONSOLE.lock();
+        let console = console.as_mut().unwrap();
+
+        for _ in 0..console.rows * 2 {
+            console.write(b"line\n");
+        }
+
+        console.page_up();
+        assert_eq!(console.scrollback, console.rows);
+
+        // Writing returns to the live screen
+        console.write(b"x\x08");
+        assert_eq!(console.scrollback, 0);
+    }
+}
@@ -0,0 +1,66 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on SMEP, SMAP and no-execute pages where the CPU supports them.
+///
+/// With SMEP the kernel faults instead of executing user pages, and with SMAP it faults on any
+/// access to user memory outside of a `UserAccessGuard`.
+pub fn init() {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+    if max_leaf >= 7 {
+        let features = unsafe { __cpuid_count(7, 0) }.ebx;
+        let mut flags = Cr4Flags::empty();
+
+        if features & (1 << 7) != 0 {
+            flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+        }
+        if features & (1 << 20) != 0 {
+            flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+            SMAP_ENABLED.store(true, Ordering::Relaxed);
+        }
+
+        unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    }
+
+    if max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0 {
+        unsafe { Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether page table entries may set `NO_EXECUTE`, which is a reserved bit otherwise
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+/// While this is alive the kernel may access user memory. Keep it to the copy itself.
+#[allow(dead_code, reason = "for copy_from_user and friends, once there are user processes")]
+pub struct UserAccessGuard {
+    _private: (),
+}
+
+impl UserAccessGuard {
+    pub fn enter() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+
+        UserAccessGuard { _private: () }
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
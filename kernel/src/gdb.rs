@@ -0,0 +1,375 @@
+//! Stub for the GDB remote serial protocol on COM1, so `gdb` can attach with
+//! `target remote` to whatever the serial port is connected to.
+//!
+//! The stub is entered on `int3`, on hardware breakpoints and single steps, and on panic. Only the
+//! registers saved in the interrupt stack frame are available, general purpose registers are
+//! reported as unavailable.
+
+use core::arch::asm;
+use crate::sync::IrqSpinlock;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use crate::memory::is_accessible;
+use crate::serial::{SerialPort, COM1};
+
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+
+const PACKET_SIZE: usize = 1024;
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Addresses of the breakpoints in DR0-DR3
+static HARDWARE_BREAKPOINTS: IrqSpinlock<[Option<u64>; 4]> = IrqSpinlock::new([None; 4]);
+
+/// What to do with the interrupted code when the debugger lets it run again
+pub enum Resume {
+    Continue,
+    Step,
+}
+
+struct Response {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    fn new() -> Self {
+        Response {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let count = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + count].copy_from_slice(&bytes[..count]);
+        self.len += count;
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.push(&[HEX_DIGITS[(byte >> 4) as usize], HEX_DIGITS[(byte & 0xf) as usize]]);
+        }
+    }
+
+    /// Marks a register of `size` bytes as unavailable
+    fn push_unavailable(&mut self, size: usize) {
+        for _ in 0..size * 2 {
+            self.push(b"x");
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits.iter().try_fold(0, |value, digit| Some(value << 4 | hex_value(*digit)? as u64))
+}
+
+/// Parses `<addr>,<len>` as sent with memory and breakpoint packets
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|byte| *byte == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+fn write_debug_address(slot: usize, addr: u64) {
+    unsafe {
+        match slot {
+            0 => asm!("mov dr0, {}", in(reg) addr, options(nomem, nostack)),
+            1 => asm!("mov dr1, {}", in(reg) addr, options(nomem, nostack)),
+            2 => asm!("mov dr2, {}", in(reg) addr, options(nomem, nostack)),
+            _ => asm!("mov dr3, {}", in(reg) addr, options(nomem, nostack)),
+        }
+    }
+}
+
+fn update_dr7(slot: usize, enabled: bool) {
+    let mut dr7: u64;
+    unsafe { asm!("mov {}, dr7", out(reg) dr7, options(nomem, nostack)) };
+
+    // Condition and length bits of 0 break on execution of a single byte
+    dr7 &= !(0xf << (16 + slot * 4));
+    if enabled {
+        dr7 |= 1 << (slot * 2);
+    } else {
+        dr7 &= !(1 << (slot * 2));
+    }
+
+    unsafe { asm!("mov dr7, {}", in(reg) dr7, options(nomem, nostack)) };
+}
+
+fn set_hardware_breakpoint(addr: u64) -> bool {
+    // The stub runs in exception handlers, so it can't wait for the lock
+    let Some(mut breakpoints) = HARDWARE_BREAKPOINTS.try_lock() else {
+        return false;
+    };
+
+    if breakpoints.contains(&Some(addr)) {
+        return true;
+    }
+
+    let Some(slot) = breakpoints.iter().position(|breakpoint| breakpoint.is_none()) else {
+        return false;
+    };
+
+    breakpoints[slot] = Some(addr);
+    write_debug_address(slot, addr);
+    update_dr7(slot, true);
+    true
+}
+
+fn clear_hardware_breakpoint(addr: u64) -> bool {
+    let Some(mut breakpoints) = HARDWARE_BREAKPOINTS.try_lock() else {
+        return false;
+    };
+
+    let Some(slot) = breakpoints.iter().position(|breakpoint| *breakpoint == Some(addr)) else {
+        return false;
+    };
+
+    breakpoints[slot] = None;
+    update_dr7(slot, false);
+    true
+}
+
+/// Reads DR6 to find out why a debug exception happened, then clears it
+pub fn take_debug_status() -> u64 {
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack));
+        asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack));
+    }
+    dr6
+}
+
+struct Connection {
+    port: SerialPort,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn receive_packet<'a>(&mut self, buf: &'a mut [u8; PACKET_SIZE]) -> &'a [u8] {
+        loop {
+            while self.read_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut overflowed = false;
+
+            loop {
+                let byte = self.read_byte();
+                if byte == b'#' {
+                    break;
+                }
+
+                checksum = checksum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    buf[len] = byte;
+                    len += 1;
+                } else {
+                    overflowed = true;
+                }
+            }
+
+            let expected = (self.read_byte(), self.read_byte());
+            let expected = hex_value(expected.0).zip(hex_value(expected.1)).map(|(high, low)| high << 4 | low);
+
+            if !overflowed && expected == Some(checksum) {
+                self.port.send(b'+');
+                return &buf[..len];
+            }
+
+            self.port.send(b'-');
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |checksum, byte| checksum.wrapping_add(*byte));
+
+        loop {
+            self.port.send(b'$');
+            for byte in data {
+                self.port.send(*byte);
+            }
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.port.send(HEX_DIGITS[(checksum & 0xf) as usize]);
+
+            loop {
+                match self.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    // A packet from GDB rather than an ack, whose body and checksum may
+                    // contain '+' or '-'. GDB retransmits it when we don't acknowledge it.
+                    b'$' => {
+                        while self.read_byte() != b'#' {}
+                        self.read_byte();
+                        self.read_byte();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> Response {
+    let mut response = Response::new();
+    response.push(b"S");
+    response.push_hex(&[signal]);
+    response
+}
+
+/// Registers in the order of GDB's amd64 target description
+fn read_registers(frame: Option<&InterruptStackFrameValue>) -> Response {
+    let mut response = Response::new();
+
+    let Some(frame) = frame else {
+        response.push_unavailable(16 * 8 + 8 + 7 * 4);
+        return response;
+    };
+
+    // rax, rbx, rcx, rdx, rsi, rdi and rbp aren't saved by the interrupt
+    response.push_unavailable(7 * 8);
+    response.push_hex(&frame.stack_pointer.as_u64().to_le_bytes());
+    // r8-r15
+    response.push_unavailable(8 * 8);
+    response.push_hex(&frame.instruction_pointer.as_u64().to_le_bytes());
+    response.push_hex(&(frame.cpu_flags.bits() as u32).to_le_bytes());
+    response.push_hex(&(frame.code_segment.0 as u32).to_le_bytes());
+    response.push_hex(&(frame.stack_segment.0 as u32).to_le_bytes());
+    // ds, es, fs and gs
+    response.push_unavailable(4 * 4);
+
+    response
+}
+
+fn read_memory(args: &[u8]) -> Response {
+    let mut response = Response::new();
+
+    match parse_range(args) {
+        Some((addr, len)) if len <= (PACKET_SIZE / 2) as u64 && is_accessible(addr, len, false) => {
+            for offset in 0..len {
+                let byte = unsafe { core::ptr::read_volatile((addr + offset) as *const u8) };
+                response.push_hex(&[byte]);
+            }
+        }
+        _ => response.push(b"E01"),
+    }
+
+    response
+}
+
+fn write_memory(args: &[u8]) -> Response {
+    let mut response = Response::new();
+
+    let Some(colon) = args.iter().position(|byte| *byte == b':') else {
+        response.push(b"E01");
+        return response;
+    };
+    let data = &args[colon + 1..];
+
+    match parse_range(&args[..colon]) {
+        Some((addr, len)) if data.len() as u64 == len * 2 && is_accessible(addr, len, true) => {
+            for (offset, digits) in data.chunks(2).enumerate() {
+                let byte = hex_value(digits[0]).zip(hex_value(digits[1])).map(|(high, low)| high << 4 | low);
+
+                let Some(byte) = byte else {
+                    response.push(b"E02");
+                    return response;
+                };
+                unsafe { core::ptr::write_volatile((addr + offset as u64) as *mut u8, byte) };
+            }
+            response.push(b"OK");
+        }
+        _ => response.push(b"E01"),
+    }
+
+    response
+}
+
+/// Updates a hardware breakpoint for `Z1`/`z1` packets. Software breakpoints (`Z0`) aren't
+/// supported as kernel text is read only, so GDB users should use `hbreak`.
+fn hardware_breakpoint(args: &[u8], set: bool) -> Response {
+    let mut response = Response::new();
+
+    let done = match args.split_first() {
+        Some((b'1', rest)) => match rest.split_first().and_then(|(_, range)| parse_range(range)) {
+            Some((addr, _kind)) if set => set_hardware_breakpoint(addr),
+            Some((addr, _kind)) => clear_hardware_breakpoint(addr),
+            None => false,
+        },
+        // Unsupported breakpoint type
+        _ => return response,
+    };
+
+    let reply: &[u8] = if done { b"OK" } else { b"E01" };
+    response.push(reply);
+    response
+}
+
+/// Talks to GDB until it resumes execution. `frame` is the state of the interrupted code, or
+/// `None` if there is nothing to resume (such as after a panic).
+pub fn enter(signal: u8, frame: Option<&InterruptStackFrameValue>) -> Resume {
+    // Everything else is stopped, so it is fine to use COM1 without its lock
+    let mut connection = Connection {
+        port: unsafe { SerialPort::new(COM1) },
+    };
+    let mut buf = [0; PACKET_SIZE];
+
+    connection.send_packet(stop_reply(signal).as_bytes());
+
+    loop {
+        let packet = connection.receive_packet(&mut buf);
+        let Some((command, args)) = packet.split_first() else {
+            continue;
+        };
+
+        let response = match command {
+            b'?' => stop_reply(signal),
+            b'g' => read_registers(frame),
+            b'm' => read_memory(args),
+            b'M' => write_memory(args),
+            b'Z' => hardware_breakpoint(args, true),
+            b'z' => hardware_breakpoint(args, false),
+            b'c' | b's' if frame.is_none() => stop_reply(signal),
+            b'c' => return Resume::Continue,
+            b's' => return Resume::Step,
+            b'D' => {
+                connection.send_packet(b"OK");
+                return Resume::Continue;
+            }
+            b'H' => {
+                let mut response = Response::new();
+                response.push(b"OK");
+                response
+            }
+            // An empty response tells GDB the packet isn't supported
+            _ => Response::new(),
+        };
+
+        connection.send_packet(response.as_bytes());
+    }
+}
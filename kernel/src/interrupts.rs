@@ -1,3 +1,4 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -56,15 +57,41 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// How many interrupt handlers are running, more than one if they nest
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
+/// Marks the current code as running in an interrupt handler until dropped. Handlers that take
+/// locks should hold one, so `IrqSpinlock::lock` can catch them waiting on a lock.
+pub struct InterruptContext {
+    _private: (),
+}
+
+impl InterruptContext {
+    pub fn enter() -> Self {
+        INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+        InterruptContext { _private: () }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) > 0
+}
 
 extern "x86-interrupt" fn spurious(_interrupt_stack_frame: InterruptStackFrame) {}
 
 extern "x86-interrupt" fn lapic_timer(_interrupt_stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
     unimplemented!()
 }
 
 extern "x86-interrupt" fn keyboard(_interrupt_stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
     unimplemented!()
 }
 
@@ -76,17 +103,54 @@ pub(super) mod exception_handlers {
         panic!("[CPU Exception] Divide Error");
     }
 
+    #[cfg(not(feature = "gdb-stub"))]
     pub(super) extern "x86-interrupt" fn debug(_interrupt_stack_frame: InterruptStackFrame) {}
+
+    #[cfg(feature = "gdb-stub")]
+    pub(super) extern "x86-interrupt" fn debug(mut interrupt_stack_frame: InterruptStackFrame) {
+        let _context = super::InterruptContext::enter();
+        crate::gdb::take_debug_status();
+        let resume = crate::gdb::enter(crate::gdb::SIGTRAP, Some(&*interrupt_stack_frame));
+        resume_from_debugger(&mut interrupt_stack_frame, resume);
+    }
+
     pub(super) extern "x86-interrupt" fn non_maskable_interrupt(
         _interrupt_stack_frame: InterruptStackFrame,
     ) {
         panic!("[CPU Exception] Non-Maskable Interrupt")
     }
 
+    #[cfg(not(feature = "gdb-stub"))]
     pub(super) extern "x86-interrupt" fn breakpoint_handler(
         _interrupt_stack_frame: InterruptStackFrame,
     ) {
     }
+
+    #[cfg(feature = "gdb-stub")]
+    pub(super) extern "x86-interrupt" fn breakpoint_handler(
+        mut interrupt_stack_frame: InterruptStackFrame,
+    ) {
+        let _context = super::InterruptContext::enter();
+        let resume = crate::gdb::enter(crate::gdb::SIGTRAP, Some(&*interrupt_stack_frame));
+        resume_from_debugger(&mut interrupt_stack_frame, resume);
+    }
+
+    /// Sets the trap flag to single step, and the resume flag so a hardware breakpoint on the
+    /// next instruction doesn't fire again straight away
+    #[cfg(feature = "gdb-stub")]
+    fn resume_from_debugger(interrupt_stack_frame: &mut InterruptStackFrame, resume: crate::gdb::Resume) {
+        use x86_64::registers::rflags::RFlags;
+
+        unsafe {
+            interrupt_stack_frame.as_mut().update(|frame| {
+                frame.cpu_flags.insert(RFlags::RESUME_FLAG);
+                match resume {
+                    crate::gdb::Resume::Continue => frame.cpu_flags.remove(RFlags::TRAP_FLAG),
+                    crate::gdb::Resume::Step => frame.cpu_flags.insert(RFlags::TRAP_FLAG),
+                }
+            })
+        };
+    }
     pub(super) extern "x86-interrupt" fn overflow(_interrupt_stack_frame: InterruptStackFrame) {
         panic!("[CPU Exception] Overflow")
     }
@@ -113,6 +177,14 @@ pub(super) mod exception_handlers {
         _interrupt_stack_frame: InterruptStackFrame,
         _error_code: u64,
     ) -> ! {
+        // Overflowing the kernel stack page faults in the guard page, and the CPU then can't push
+        // the page fault's frame onto the same stack, so this is where overflows end up
+        if let Ok(addr) = Cr2::read() {
+            if crate::memory::is_stack_guard_page(addr) {
+                panic!("[CPU Exception] Double Fault: kernel stack overflow at {:?}", addr)
+            }
+        }
+
         panic!("[CPU Exception] Double Fault")
     }
 
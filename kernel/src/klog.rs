@@ -0,0 +1,185 @@
+use core::fmt;
+use core::fmt::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::sync::IrqSpinlock;
+
+/// Size of the in-memory log, older messages are overwritten once it fills up
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+/// Longer messages are truncated
+const MAX_LINE_LENGTH: usize = 512;
+const MAX_SINKS: usize = 4;
+
+/// Somewhere log messages are written to as they are logged
+pub trait Sink: Sync {
+    /// `line` is the formatted message, including the level and trailing newline
+    fn write(&self, level: Level, line: &str);
+}
+
+/// Writes to QEMU's 0xE9 debug console
+pub struct DebugconSink;
+
+impl Sink for DebugconSink {
+    fn write(&self, _level: Level, line: &str) {
+        let _ = crate::console::DebugCons.write_str(line);
+    }
+}
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    written: u64, // Total bytes ever written, positions into the log are relative to this
+}
+
+impl LogBuffer {
+    fn push(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.data[(self.written % LOG_BUFFER_SIZE as u64) as usize] = *byte;
+            self.written += 1;
+        }
+    }
+
+    #[cfg(test)]
+    fn read(&self, position: &mut u64, buf: &mut [u8]) -> usize {
+        // Skip ahead if the reader fell behind and its messages were overwritten
+        let oldest = self.written.saturating_sub(LOG_BUFFER_SIZE as u64);
+        *position = (*position).max(oldest);
+
+        let count = buf.len().min((self.written - *position) as usize);
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.data[((*position + i as u64) % LOG_BUFFER_SIZE as u64) as usize];
+        }
+
+        *position += count as u64;
+        count
+    }
+}
+
+/// Formats into a fixed buffer so logging works before the heap is set up
+struct LineBuffer {
+    data: [u8; MAX_LINE_LENGTH],
+    len: usize,
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Always leave room for the newline
+        let available = MAX_LINE_LENGTH - 1 - self.len;
+        let mut count = s.len().min(available);
+
+        // Don't split a UTF-8 character
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+
+        self.data[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+static BUFFER: IrqSpinlock<LogBuffer> = IrqSpinlock::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    written: 0,
+});
+static SINKS: IrqSpinlock<[Option<&'static dyn Sink>; MAX_SINKS]> = IrqSpinlock::new([None; MAX_SINKS]);
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Backend for the `log` crate, so `log::info!` and friends (including in dependencies) end up
+/// in the kernel log
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            write_line(record.level(), *record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel log as the `log` backend. Call as early as possible, messages logged
+/// before this are dropped.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("Logger already initialised");
+    set_level(LevelFilter::Info);
+}
+
+/// Messages less severe than `level` are discarded
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Registers a sink to receive every message logged from now on
+pub fn add_sink(sink: &'static dyn Sink) {
+    let mut sinks = SINKS.lock();
+
+    let slot = sinks.iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many log sinks");
+
+    *slot = Some(sink);
+}
+
+fn write_line(level: Level, args: fmt::Arguments) {
+    let mut line = LineBuffer {
+        data: [0; MAX_LINE_LENGTH],
+        len: 0,
+    };
+    let _ = write!(line, "[{:>5}] {}", level, args);
+    line.data[line.len] = b'\n';
+    line.len += 1;
+
+    // Only whole characters were copied in, so this is valid UTF-8
+    let line = core::str::from_utf8(&line.data[..line.len]).unwrap();
+
+    BUFFER.lock().push(line.as_bytes());
+
+    // Copy the sinks out so a sink that logs doesn't deadlock
+    let sinks = *SINKS.lock();
+    for sink in sinks.iter().flatten() {
+        sink.write(level, line);
+    }
+}
+
+/// Copies log text starting at `position` into `buf`, returning how many bytes were read.
+/// `position` is advanced past what was read, so repeated calls follow the log like `/proc/kmsg`.
+#[cfg(test)]
+pub fn read(position: &mut u64, buf: &mut [u8]) -> usize {
+    BUFFER.lock().read(position, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn reader_skips_overwritten_messages() {
+        let mut buffer = LogBuffer {
+            data: [0; LOG_BUFFER_SIZE],
+            written: 0,
+        };
+        buffer.push(&[b'a'; LOG_BUFFER_SIZE]);
+        buffer.push(b"xyz");
+
+        let mut position = 0;
+        let mut buf = [0; 4];
+        assert_eq!(buffer.read(&mut position, &mut buf), 4);
+        assert_eq!(position, 7);
+        assert_eq!(&buf, b"aaaa");
+    }
+
+    #[test_case]
+    fn reader_follows_log() {
+        let mut position = BUFFER.lock().written;
+
+        log::info!("test message");
+
+        let mut buf = [0; 64];
+        let count = read(&mut position, &mut buf);
+        assert_eq!(&buf[..count], b"[ INFO] test message\n");
+    }
+}
@@ -0,0 +1,223 @@
+//! Lock validator, enabled with the `lock-validator` feature. Records the order `IrqSpinlock`s
+//! are taken in and panics on a lock taken twice, or on two locks taken in both orders, which
+//! could deadlock even if it didn't this time.
+//!
+//! Locks are told apart by address, so only `static` locks are validated. A lock on the stack or
+//! heap can be freed and its address reused by an unrelated lock, which would inherit its
+//! recorded orders. There is a single CPU and no threads, so one list of held locks covers
+//! everything, including interrupt handlers nested inside locked code.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::backtrace::{object_name, read_u16, read_u32, read_u64, Trace};
+
+const MAX_HELD: usize = 16;
+/// At most 64, so `find_path` can track visited orders in a `u64`
+const MAX_ORDERS: usize = 64;
+const _: () = assert!(MAX_ORDERS <= u64::BITS as usize);
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+struct Held {
+    lock: usize,
+    taken_at: Trace,
+}
+
+/// `before` was held when `after` was taken
+struct LockOrder {
+    before: usize,
+    after: usize,
+    taken_at: Trace,
+}
+
+struct Validator {
+    held: [Option<Held>; MAX_HELD],
+    orders: [Option<LockOrder>; MAX_ORDERS],
+}
+
+impl Validator {
+    fn is_ordered(&self, before: usize, after: usize) -> bool {
+        self.orders.iter().flatten().any(|order| order.before == before && order.after == after)
+    }
+
+    /// Finds the first order in a chain from `from` to `to`, meaning `to` has been taken (perhaps
+    /// indirectly) while `from` was held
+    fn find_path(&self, from: usize, to: usize) -> Option<&LockOrder> {
+        self.search(from, to, &mut 0)
+    }
+
+    /// Depth first search that follows each order at most once, marking them in `visited`
+    fn search(&self, from: usize, to: usize, visited: &mut u64) -> Option<&LockOrder> {
+        for (index, order) in self.orders.iter().enumerate() {
+            let Some(order) = order else {
+                continue;
+            };
+            if order.before != from || *visited & (1 << index) != 0 {
+                continue;
+            }
+            *visited |= 1 << index;
+
+            if order.after == to || self.search(order.after, to, visited).is_some() {
+                return Some(order);
+            }
+        }
+
+        None
+    }
+}
+
+/// Accessed with interrupts disabled by the lock being validated. It is only ever contended if a
+/// check itself takes a lock, in which case that lock isn't validated.
+static VALIDATOR: Mutex<Validator> = Mutex::new(Validator {
+    held: [const { None }; MAX_HELD],
+    orders: [const { None }; MAX_ORDERS],
+});
+
+/// Set once a problem is found, so the locks taken while panicking aren't checked
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Where the kernel's writable segments were loaded, which is where its statics are. Empty until
+/// `init`, so nothing is validated before then.
+static STATICS_START: AtomicU64 = AtomicU64::new(0);
+static STATICS_END: AtomicU64 = AtomicU64::new(0);
+
+/// Finds the kernel's statics from the program headers of its ELF file, `len` bytes at `kernel`,
+/// which was loaded at `image_offset`
+///
+/// # Safety
+/// `kernel` must point to the kernel's ELF file, `len` bytes long
+pub unsafe fn init(kernel: *const u8, len: usize, image_offset: u64) {
+    let kernel = unsafe { core::slice::from_raw_parts(kernel, len) };
+
+    let (Some(headers), Some(header_size), Some(count)) = (
+        read_u64(kernel, 0x20),
+        read_u16(kernel, 0x36),
+        read_u16(kernel, 0x38),
+    ) else {
+        return;
+    };
+
+    let mut start = u64::MAX;
+    let mut end = 0;
+
+    for index in 0..count as usize {
+        let base = headers as usize + index * header_size as usize;
+
+        let (Some(kind), Some(flags), Some(addr), Some(size)) = (
+            read_u32(kernel, base),
+            read_u32(kernel, base + 4),
+            read_u64(kernel, base + 16),
+            read_u64(kernel, base + 40),
+        ) else {
+            return;
+        };
+
+        if kind == PT_LOAD && flags & PF_W != 0 {
+            start = start.min(image_offset + addr);
+            end = end.max(image_offset + addr + size);
+        }
+    }
+
+    if start < end {
+        STATICS_START.store(start, Ordering::Relaxed);
+        STATICS_END.store(end, Ordering::Relaxed);
+    }
+}
+
+/// Whether `lock` is a static, and so keeps its address for good
+fn is_static(lock: usize) -> bool {
+    (STATICS_START.load(Ordering::Relaxed)..STATICS_END.load(Ordering::Relaxed)).contains(&(lock as u64))
+}
+
+struct LockName(usize);
+
+impl fmt::Display for LockName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match object_name(self.0 as u64) {
+            Some(name) => write!(f, "{:#} ({:#x})", name, self.0),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+fn fail(args: fmt::Arguments) -> ! {
+    DISABLED.store(true, Ordering::Relaxed);
+    panic!("{}", args)
+}
+
+/// Called before waiting for `lock`. Panics if it is already held, or if one of the held locks
+/// has been taken while holding `lock` before.
+pub fn acquire(lock: usize) {
+    if DISABLED.load(Ordering::Relaxed) || !is_static(lock) {
+        return;
+    }
+    let Some(mut validator) = VALIDATOR.try_lock() else {
+        return;
+    };
+
+    let taken_at = Trace::capture();
+    let held: [Option<usize>; MAX_HELD] = core::array::from_fn(|i| validator.held[i].as_ref().map(|held| held.lock));
+
+    if let Some(first) = validator.held.iter().flatten().find(|held| held.lock == lock) {
+        let first = first.taken_at.clone();
+        drop(validator);
+        fail(format_args!(
+            "Lock {} taken while already held\nFirst taken at:\n{}Taken again at:\n{}",
+            LockName(lock), first, taken_at
+        ));
+    }
+
+    for before in held.into_iter().flatten() {
+        if let Some(earlier) = validator.find_path(lock, before) {
+            let earlier = earlier.taken_at.clone();
+            drop(validator);
+            fail(format_args!(
+                "Lock order inversion: {} taken while holding {}, which has been taken while holding it\n\
+                 Earlier order established at:\n{}Inverted at:\n{}",
+                LockName(lock), LockName(before), earlier, taken_at
+            ));
+        }
+
+        if !validator.is_ordered(before, lock) {
+            // Once full, new orders aren't checked
+            if let Some(slot) = validator.orders.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(LockOrder {
+                    before,
+                    after: lock,
+                    taken_at: taken_at.clone(),
+                });
+            }
+        }
+    }
+
+    push(&mut validator, lock, taken_at);
+}
+
+/// Called after `lock` was taken without waiting for it. A failed `try_lock` can't deadlock, so
+/// this only records that it is held.
+pub fn acquired(lock: usize) {
+    if DISABLED.load(Ordering::Relaxed) || !is_static(lock) {
+        return;
+    }
+    if let Some(mut validator) = VALIDATOR.try_lock() {
+        push(&mut validator, lock, Trace::capture());
+    }
+}
+
+fn push(validator: &mut Validator, lock: usize, taken_at: Trace) {
+    // Past this many nested locks, the extra ones aren't checked
+    if let Some(slot) = validator.held.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(Held { lock, taken_at });
+    }
+}
+
+/// Called when `lock` is released, which doesn't have to be in the order locks were taken
+pub fn release(lock: usize) {
+    if let Some(mut validator) = VALIDATOR.try_lock() {
+        if let Some(slot) = validator.held.iter_mut().find(|slot| slot.as_ref().is_some_and(|held| held.lock == lock)) {
+            *slot = None;
+        }
+    }
+}
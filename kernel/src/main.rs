@@ -1,4 +1,7 @@
 #![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 extern crate alloc;
@@ -6,10 +9,23 @@ extern crate alloc;
 use alloc::boxed::Box;
 use core::fmt::Write;
 
+mod backtrace;
 mod console;
+mod cpu;
 mod interrupts;
 mod gdt;
+#[cfg(feature = "gdb-stub")]
+mod gdb;
+mod klog;
+#[cfg(feature = "lock-validator")]
+mod lockdep;
 mod memory;
+mod qemu;
+mod rtc;
+mod serial;
+mod sync;
+#[cfg(test)]
+mod testing;
 
 use crate::console::Console;
 use alloc::fmt;
@@ -92,7 +108,25 @@ static mut PANIC_FRAMEBUFFER: Option<*mut FrameBuffer> = None;
 /// code running in the system, so it can have complete control without any rogue threads interfering.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    #[cfg(test)]
+    debug_println!("[failed]");
     debug_println!("panicked: {}", info);
+    let _ = backtrace::print(&mut console::DebugCons);
+
+    // Don't wait on the lock, the panic may have happened while it was held. With the GDB stub
+    // COM1 belongs to GDB, which is told about the panic below.
+    #[cfg(not(feature = "gdb-stub"))]
+    if let Some(mut serial) = serial::SERIAL.try_lock() {
+        if let Some(serial) = serial.as_mut() {
+            let _ = writeln!(serial, "panicked: {}", info);
+        }
+    }
+
+    // Tests and CI runs have nobody to look at the screen, so fail once the message is out
+    if cfg!(test) || qemu::ci_mode() {
+        qemu::exit_qemu(qemu::QemuExitCode::Failed);
+    }
+
     if let Some(framebuffer) = unsafe { PANIC_FRAMEBUFFER } {
         let framebuffer = unsafe {&mut *framebuffer };
 
@@ -115,17 +149,26 @@ fn panic(info: &PanicInfo) -> ! {
             frame_buffer: framebuffer
         };
 
-        let _ = write!(&mut console, "panicked: {}", info);
+        let _ = writeln!(&mut console, "panicked: {}", info);
+        let _ = backtrace::print(&mut console);
     }
 
+    #[cfg(feature = "gdb-stub")]
+    gdb::enter(gdb::SIGABRT, None);
+
     loop {}
 }
 
 pub const HEAP_START: u64 = 0x_ffff_9000_0000_0000;
+/// The bootloader leaves the first page here unmapped as a guard page, the stack starts above it
+pub const KERNEL_STACK_START: u64 = 0xffff_f700_0000_0000;
+/// Not including the guard page below it
+pub const KERNEL_STACK_SIZE: u64 = 80 * 1024;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
-    config.mappings.kernel_stack = Mapping::FixedAddress(0xffff_f700_0000_0000);
+    config.mappings.kernel_stack = Mapping::FixedAddress(KERNEL_STACK_START);
+    config.kernel_stack_size = KERNEL_STACK_SIZE;
     config.mappings.physical_memory = Some(Mapping::FixedAddress(0xffff_e000_0000_0000)); // 16 TiB of RAM ought to be enough for anybody
     config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
     config.mappings.dynamic_range_end = Some(0xffff_8fff_ffff_ffff);
@@ -140,12 +183,49 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
 
     gdt::init();
     interrupts::init_idt();
+    cpu::init();
+    serial::init();
+    qemu::init();
+
+    klog::init();
+    klog::add_sink(&klog::DebugconSink);
+    #[cfg(not(feature = "gdb-stub"))]
+    klog::add_sink(&serial::SerialSink);
+
+    rtc::init();
 
     let physical_offset = boot_info.physical_memory_offset.into_option().expect("Expected recursive index");
 
-    let (_mapper, _pmm) = unsafe { memory::init(physical_offset, &boot_info.memory_regions) };
+    unsafe {
+        backtrace::init(
+            (physical_offset + boot_info.kernel_addr) as *const u8,
+            boot_info.kernel_len as usize,
+            boot_info.kernel_image_offset,
+        )
+    };
+
+    #[cfg(feature = "lock-validator")]
+    unsafe {
+        lockdep::init(
+            (physical_offset + boot_info.kernel_addr) as *const u8,
+            boot_info.kernel_len as usize,
+            boot_info.kernel_image_offset,
+        )
+    };
+
+    unsafe { memory::init(physical_offset, &boot_info.memory_regions) };
+
+    #[cfg(feature = "gdb-stub")]
+    {
+        log::info!("Waiting for GDB on COM1");
+        x86_64::instructions::interrupts::int3();
+    }
+
+    *console::CONSOLE.lock() = Some(Console::new(framebuffer));
+    klog::add_sink(&console::ConsoleSink);
 
-    let mut console = Console::new(framebuffer);
+    #[cfg(test)]
+    test_main();
 
     for i in 0..INITIAL_HEAP_SIZE {
         let x = Box::new(i);
@@ -164,7 +244,11 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     assert_eq!(*heap_value_1, 41);
     assert_eq!(*heap_value_2, 13);
 
-    boot_println!(&mut console, "Boot complete!");
+    log::info!("Boot complete!");
+    if qemu::ci_mode() {
+        qemu::exit_qemu(qemu::QemuExitCode::Success);
+    }
+
     loop {
         hlt();
     }
@@ -1,44 +1,204 @@
+use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::{Display, Formatter};
-use core::mem::zeroed;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use core::ptr::slice_from_raw_parts_mut;
-use linked_list_allocator::LockedHeap;
+use core::ptr::{null_mut, slice_from_raw_parts_mut, NonNull};
+use linked_list_allocator::Heap;
+use crate::sync::IrqSpinlock;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::{PhysAddr, VirtAddr};
-use crate::HEAP_START;
+use crate::{HEAP_START, KERNEL_STACK_SIZE, KERNEL_STACK_START};
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: HeapManager = HeapManager::new(MAX_HEAP_SIZE);
 pub const INITIAL_HEAP_SIZE: u64 = 100 * 1024;
+pub const MAX_HEAP_SIZE: u64 = 64 * 1024 * 1024;
+/// The heap grows by at least this much at a time, so small allocations don't map one page each
+const HEAP_GROWTH_STEP: u64 = 64 * 1024;
+
+/// Lock order: the heap lock (taken inside the allocator) before `MAPPER` before `PMM`.
+/// Don't allocate while holding either of these, as growing the heap needs them.
+pub static MAPPER: IrqSpinlock<Option<OffsetPageTable<'static>>> = IrqSpinlock::new(None);
+pub static PMM: IrqSpinlock<Option<PhysicalMemoryManager<'static>>> = IrqSpinlock::new(None);
 
 
 /// # Safety
 /// Can only be called once. Physical offset must be correct
-pub unsafe fn init(physical_offset: u64, memory_regions: &'static MemoryRegions) -> (OffsetPageTable<'static>, PhysicalMemoryManager<'static>) {
-    let mut mapper = init_page_table(physical_offset);
-
-    let mut pmm = PhysicalMemoryManager::new(&memory_regions, VirtAddr::new(physical_offset));
-
-    let heap_start = VirtAddr::new(HEAP_START);
-    let heap_end = heap_start + INITIAL_HEAP_SIZE - 1u64;
-    let page_range = Page::range_inclusive(
-        Page::containing_address(heap_start),
-        Page::containing_address(heap_end),
-    );
-
-    for page in page_range {
-        let frame = pmm
-            .allocate_frame()
-            .expect("Failed to initialise heap");
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, &mut pmm).expect("Failed to initialise heap").flush();
+pub unsafe fn init(physical_offset: u64, memory_regions: &'static MemoryRegions) {
+    let mapper = init_page_table(physical_offset);
+
+    let pmm = PhysicalMemoryManager::new(&memory_regions, VirtAddr::new(physical_offset));
+
+    *MAPPER.lock() = Some(mapper);
+    *PMM.lock() = Some(pmm);
+
+    unsafe { ALLOCATOR.init(INITIAL_HEAP_SIZE) }.expect("Failed to initialise heap");
+}
+
+#[derive(Debug)]
+pub enum HeapError {
+    /// Growing would take the heap past its configured maximum
+    MaxSizeReached,
+    /// There were no physical frames left to back the new pages
+    OutOfMemory,
+}
+
+/// Kernel heap that maps more pages from the PMM when the allocator runs out, up to `max_size`.
+///
+/// Allocations that still can't be satisfied return null instead of panicking, so fallible
+/// APIs like `Vec::try_reserve` report the failure to their caller.
+pub struct HeapManager {
+    heap: IrqSpinlock<Heap>,
+    max_size: u64,
+}
+
+impl HeapManager {
+    pub const fn new(max_size: u64) -> Self {
+        HeapManager {
+            heap: IrqSpinlock::new(Heap::empty()),
+            max_size,
         }
     }
 
-    unsafe { ALLOCATOR.lock().init(heap_start.as_mut_ptr(), INITIAL_HEAP_SIZE as usize) };
-    (mapper, pmm)
+    /// # Safety
+    /// Can only be called once, after `MAPPER` and `PMM` have been set up.
+    unsafe fn init(&self, size: u64) -> Result<(), HeapError> {
+        let mut heap = self.heap.lock();
+        let heap_start = VirtAddr::new(HEAP_START);
+
+        if Self::map_pages(heap_start, size) < size {
+            return Err(HeapError::OutOfMemory);
+        }
+
+        unsafe { heap.init(heap_start.as_mut_ptr(), size as usize) };
+        Ok(())
+    }
+
+    /// Maps `size` bytes of fresh frames at `start`, which must be page aligned.
+    /// Returns how many bytes were mapped, which is less than `size` if it ran out of frames.
+    fn map_pages(start: VirtAddr, size: u64) -> u64 {
+        let mut mapper = MAPPER.lock();
+        let mut pmm = PMM.lock();
+        let (mapper, pmm) = (mapper.as_mut().unwrap(), pmm.as_mut().unwrap());
+
+        let page_range = Page::<Size4KiB>::range(
+            Page::containing_address(start),
+            Page::containing_address(start + size),
+        );
+
+        let mut mapped = 0;
+        for page in page_range {
+            let Some(frame) = pmm.allocate_frame() else {
+                break;
+            };
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            if crate::cpu::nx_enabled() {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+
+            match unsafe { mapper.map_to(page, frame, flags, pmm) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { pmm.deallocate_frame(frame) };
+                    break;
+                }
+            }
+
+            mapped += page.size();
+        }
+
+        mapped
+    }
+
+    /// Extends `heap` by at least `min_size` bytes.
+    fn grow(&self, heap: &mut Heap, min_size: u64) -> Result<(), HeapError> {
+        let current = heap.size() as u64;
+        let remaining = self.max_size.saturating_sub(current);
+
+        let wanted = min_size.max(HEAP_GROWTH_STEP).next_multiple_of(4096);
+        let size = wanted.min(remaining);
+
+        if size < min_size {
+            return Err(HeapError::MaxSizeReached);
+        }
+
+        let mapped = Self::map_pages(VirtAddr::from_ptr(heap.top()), size);
+        if mapped > 0 {
+            unsafe { heap.extend(mapped as usize) };
+        }
+
+        if mapped < min_size {
+            Err(HeapError::OutOfMemory)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for HeapManager {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // The new space may not join an existing free block, so ask for room to align as well
+        let min_size = (layout.size() + layout.align()) as u64;
+
+        match self.grow(&mut heap, min_size) {
+            Ok(()) => heap
+                .allocate_first_fit(layout)
+                .map_or(null_mut(), |ptr| ptr.as_ptr()),
+            Err(_) => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout) }
+    }
+}
+
+/// Whether `addr` lies in the unmapped guard page below a kernel stack
+pub fn is_stack_guard_page(addr: VirtAddr) -> bool {
+    Page::<Size4KiB>::containing_address(addr) == Page::containing_address(VirtAddr::new(KERNEL_STACK_START))
+}
+
+/// Whether `len` bytes at `addr` are all on the boot kernel stack, above its guard page
+pub fn is_kernel_stack(addr: u64, len: u64) -> bool {
+    let bottom = KERNEL_STACK_START + Size4KiB::SIZE;
+    addr >= bottom && addr.checked_add(len).is_some_and(|end| end <= bottom + KERNEL_STACK_SIZE)
+}
+
+/// Whether the kernel can access `len` bytes at `addr` without faulting, or `false` if the page
+/// tables are unavailable
+pub fn is_accessible(addr: u64, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+
+    // This is used from panics and the debugger, which may have interrupted code holding the
+    // mapper lock, so don't wait for it
+    let Some(mapper) = MAPPER.try_lock() else {
+        return false;
+    };
+    let Some(mapper) = mapper.as_ref() else {
+        return false;
+    };
+    let (Ok(start), Some(Ok(end))) = (
+        VirtAddr::try_new(addr),
+        addr.checked_add(len - 1).map(VirtAddr::try_new),
+    ) else {
+        return false;
+    };
+
+    Page::<Size4KiB>::range_inclusive(Page::containing_address(start), Page::containing_address(end))
+        .all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => !write || flags.contains(PageTableFlags::WRITABLE),
+            _ => false,
+        })
 }
 
 fn init_page_table(physical_offset: u64) -> OffsetPageTable<'static> {
@@ -56,9 +216,15 @@ fn init_page_table(physical_offset: u64) -> OffsetPageTable<'static> {
 #[derive(Debug)]
 pub struct PhysicalMemoryManager<'a> {
     bitmap: &'a mut [u64], // 0 for free, 1 for used
-    physical_offset: VirtAddr
+    physical_offset: VirtAddr,
+    next_free: usize, // Index into bitmap, every entry before it is fully used
+    freed: [usize; FREED_FRAMES], // Recently freed frame indices, handed out before scanning
+    freed_len: usize,
 }
 
+/// How many freed frames the PMM remembers for quick reuse.
+const FREED_FRAMES: usize = 64;
+
 impl Display for PhysicalMemoryManager<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         writeln!(f, "Physical address: {:?}", self.physical_offset)?;
@@ -77,14 +243,20 @@ impl Display for PhysicalMemoryManager<'_> {
 }
 
 impl<'a> PhysicalMemoryManager<'a> {
+    fn frame_from_index(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * 4096))
+    }
+
     fn set_frame(&mut self, frame: PhysFrame) {
         self.bitmap[frame.start_address().as_u64() as usize / (4096 * 64)]
             |= 1 << (frame.start_address().as_u64() / 4096) % 64;
     }
 
     fn clear_frame(&mut self, frame: PhysFrame) {
-        self.bitmap[frame.start_address().as_u64() as usize / (4096 * 64)]
-            &= !(1 << (frame.start_address().as_u64() / 4096) % 64);
+        let idx = frame.start_address().as_u64() as usize / (4096 * 64);
+
+        self.bitmap[idx] &= !(1 << (frame.start_address().as_u64() / 4096) % 64);
+        self.next_free = self.next_free.min(idx);
     }
 
     fn new(memory_regions: &'static MemoryRegions, physical_offset: VirtAddr) -> Self {
@@ -93,67 +265,136 @@ impl<'a> PhysicalMemoryManager<'a> {
             .max()
             .unwrap();
 
-        // This trick rounds up instead of down
-        let region_size: usize = ((highest_address + 4096 * 8 - 1) / (4096 * 8)) as usize;
+        let frame_count = highest_address.div_ceil(4096);
+        let bitmap_len = frame_count.div_ceil(64) as usize;
+        let bitmap_size = bitmap_len as u64 * 8;
 
         let bitmap_region = memory_regions.iter()
             .filter(|region| region.kind == MemoryRegionKind::Usable)
-            .filter(|region| region.end - region.start >= region_size as u64)
+            .filter(|region| region.end - region.start >= bitmap_size)
             .next().unwrap();
 
-        let bitmap = slice_from_raw_parts_mut((physical_offset.as_u64() + bitmap_region.start) as *mut u64, region_size / 8);
+        let bitmap = slice_from_raw_parts_mut((physical_offset.as_u64() + bitmap_region.start) as *mut u64, bitmap_len);
 
         let bitmap = unsafe { &mut *bitmap };
 
-        for mem in &mut *bitmap {
-            *mem = unsafe { zeroed::<u64>() };
-        }
+        // Anything not covered by a usable region (including holes in the memory map) stays used
+        bitmap.fill(u64::MAX);
 
         let mut pmm = PhysicalMemoryManager {
             bitmap,
-            physical_offset
+            physical_offset,
+            next_free: 0,
+            freed: [0; FREED_FRAMES],
+            freed_len: 0,
         };
 
+        for region in memory_regions.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable) {
+            // Only hand out frames that lie entirely inside the region
+            let frame_range = PhysFrame::range(
+                PhysFrame::containing_address(PhysAddr::new(region.start).align_up(4096u64)),
+                PhysFrame::containing_address(PhysAddr::new(region.end).align_down(4096u64)), // End address is exclusive
+            );
+
+            for frame in frame_range {
+                pmm.clear_frame(frame);
+            }
+        }
+
         let bitmap_range = PhysFrame::range_inclusive(
             PhysFrame::containing_address(PhysAddr::new(bitmap_region.start)),
-            PhysFrame::containing_address(PhysAddr::new(bitmap_region.end - 1)), // End address is exclusive
+            PhysFrame::containing_address(PhysAddr::new(bitmap_region.start + bitmap_size - 1)),
         );
 
-
         for frame in bitmap_range {
             pmm.set_frame(frame);
         }
 
-        for region in memory_regions.iter()
-            .filter(|region| region.kind != MemoryRegionKind::Usable) {
-            let frame_range = PhysFrame::range_inclusive(
-                PhysFrame::containing_address(PhysAddr::new(region.start)),
-                PhysFrame::containing_address(PhysAddr::new(region.end - 1)), // End address is exclusive
-            );
+        pmm
+    }
 
-            for frame in frame_range {
-                pmm.set_frame(frame);
+    /// Allocates `count` physically contiguous frames, for devices that DMA into memory.
+    #[allow(dead_code, reason = "only the tests use this until there are DMA-capable drivers")]
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        if count == 0 {
+            return None;
+        }
+
+        let total = self.bitmap.len() * 64;
+        let mut start = self.next_free * 64;
+        let mut index = start;
+
+        while index < total {
+            let entry = self.bitmap[index / 64];
+
+            if entry == u64::MAX {
+                // Skip over fully used entries 64 frames at a time
+                index = (index / 64 + 1) * 64;
+                start = index;
+                continue;
+            }
+
+            if entry & (1 << (index % 64)) != 0 {
+                start = index + 1;
+            } else if index + 1 - start == count {
+                let range = PhysFrame::range(
+                    Self::frame_from_index(start),
+                    Self::frame_from_index(start + count),
+                );
+
+                for frame in range {
+                    self.set_frame(frame);
+                }
+
+                return Some(range);
             }
+
+            index += 1;
         }
 
-        pmm
+        None
+    }
+
+    /// # Safety
+    /// The frames must have come from `allocate_contiguous` and must no longer be in use.
+    #[allow(dead_code, reason = "only the tests use this until there are DMA-capable drivers")]
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.clear_frame(frame);
+        }
     }
 }
 
 unsafe impl<'a> FrameAllocator<Size4KiB> for PhysicalMemoryManager<'a> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        for (idx, entry) in self.bitmap.iter().enumerate() {
-            if *entry != u64::MAX {
-                let frame = PhysFrame::containing_address(
-                    PhysAddr::new((idx as u64 * 64 + entry.trailing_ones() as u64) * 4096)
-                );
+        while self.freed_len > 0 {
+            self.freed_len -= 1;
+            let index = self.freed[self.freed_len];
+
+            // allocate_contiguous may have taken it since it was freed
+            if self.bitmap[index / 64] & (1 << (index % 64)) == 0 {
+                let frame = Self::frame_from_index(index);
+                self.set_frame(frame);
+
+                return Some(frame)
+            }
+        }
+
+        for idx in self.next_free..self.bitmap.len() {
+            let entry = self.bitmap[idx];
 
+            if entry != u64::MAX {
+                let frame = Self::frame_from_index(idx * 64 + entry.trailing_ones() as usize);
+
+                self.next_free = idx;
                 self.set_frame(frame);
 
                 return Some(frame)
             }
         }
 
+        self.next_free = self.bitmap.len();
         None
     }
 }
@@ -162,5 +403,50 @@ unsafe impl<'a> FrameAllocator<Size4KiB> for PhysicalMemoryManager<'a> {
 impl<'a> FrameDeallocator<Size4KiB> for PhysicalMemoryManager<'a> {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
         self.clear_frame(frame);
+
+        if self.freed_len < FREED_FRAMES {
+            self.freed[self.freed_len] = frame.start_address().as_u64() as usize / 4096;
+            self.freed_len += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn freed_frame_is_reused() {
+        let mut pmm = PMM.lock();
+        let pmm = pmm.as_mut().unwrap();
+
+        let frame = pmm.allocate_frame().unwrap();
+        unsafe { pmm.deallocate_frame(frame) };
+        assert_eq!(pmm.allocate_frame(), Some(frame));
+        unsafe { pmm.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn contiguous_frames_are_reserved() {
+        let mut pmm = PMM.lock();
+        let pmm = pmm.as_mut().unwrap();
+
+        let range = pmm.allocate_contiguous(16).unwrap();
+        assert_eq!(range.end - range.start, 16);
+
+        let frame = pmm.allocate_frame().unwrap();
+        assert!(frame < range.start || frame >= range.end);
+
+        unsafe {
+            pmm.deallocate_frame(frame);
+            pmm.deallocate_contiguous(range);
+        }
+    }
+
+    #[test_case]
+    fn heap_grows_past_initial_size() {
+        let large = vec![7u8; 2 * INITIAL_HEAP_SIZE as usize];
+        assert!(large.iter().all(|byte| *byte == 7));
     }
 }
@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::hlt;
+use x86_64::instructions::port::Port;
+
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FW_CFG_FILE_NAME_LENGTH: usize = 56;
+
+/// fw_cfg file the runner adds in CI mode, telling the kernel to exit QEMU when it is done
+const CI_FILE: &[u8] = b"opt/benchix/ci";
+
+static CI_MODE: AtomicBool = AtomicBool::new(false);
+
+/// QEMU exits with `(code << 1) | 1`, so neither of these can be confused with QEMU's own exit
+/// statuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU through the `isa-debug-exit` device at port 0xf4, which the runner adds for tests
+/// and CI runs
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { Port::new(0xf4).write(code as u32) };
+
+    // Only reached if the device is missing
+    loop {
+        hlt();
+    }
+}
+
+fn read_fw_cfg(buf: &mut [u8]) {
+    let mut data = Port::<u8>::new(FW_CFG_DATA);
+
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
+
+fn select_fw_cfg(key: u16) {
+    unsafe { Port::<u16>::new(FW_CFG_SELECTOR).write(key) };
+}
+
+/// Whether the fw_cfg directory has a file called `name`. False when not running under QEMU.
+fn fw_cfg_has_file(name: &[u8]) -> bool {
+    let mut signature = [0; 4];
+    select_fw_cfg(FW_CFG_SIGNATURE);
+    read_fw_cfg(&mut signature);
+    if &signature != b"QEMU" {
+        return false;
+    }
+
+    let mut count = [0; 4];
+    select_fw_cfg(FW_CFG_FILE_DIR);
+    read_fw_cfg(&mut count);
+
+    // Each entry is a big endian u32 size, u16 selector, u16 reserved and a NUL padded name
+    (0..u32::from_be_bytes(count)).any(|_| {
+        let mut entry = [0; 8 + FW_CFG_FILE_NAME_LENGTH];
+        read_fw_cfg(&mut entry);
+
+        let entry_name = &entry[8..];
+        let len = entry_name.iter().position(|byte| *byte == 0).unwrap_or(FW_CFG_FILE_NAME_LENGTH);
+        &entry_name[..len] == name
+    })
+}
+
+/// Checks whether the runner started us in CI mode
+pub fn init() {
+    CI_MODE.store(fw_cfg_has_file(CI_FILE), Ordering::Relaxed);
+}
+
+/// In CI mode the kernel exits QEMU once booted, or with a failure on panic
+pub fn ci_mode() -> bool {
+    CI_MODE.load(Ordering::Relaxed)
+}
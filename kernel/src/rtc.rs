@@ -0,0 +1,251 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::sync::IrqSpinlock;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Set in the index port to keep NMIs masked while it is selected
+const NMI_DISABLE: u8 = 0x80;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_D: u8 = 0x0d;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOUR_FORMAT_24: u8 = 1 << 1;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+// PIT channel 2, which can be polled without an interrupt, used to measure the TSC frequency
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61; // Bit 0 gates channel 2, bit 1 connects it to the speaker
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// Channel 2, low then high byte, mode 0 (output goes high once the count reaches zero)
+const PIT_ONE_SHOT: u8 = 0b1011_0000;
+const PIT_OUTPUT: u8 = 1 << 5;
+const CALIBRATION_MS: u64 = 10;
+
+/// The index and data ports have to be used as a pair
+static CMOS: IrqSpinlock<Cmos> = IrqSpinlock::new(Cmos);
+
+/// Unix time read from the RTC by `init`
+static BOOT_TIME: AtomicI64 = AtomicI64::new(0);
+/// TSC value when `BOOT_TIME` was read
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+/// TSC ticks per second, or 0 before `init`
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Seconds added to the boot time plus uptime to get the realtime clock, changed with
+/// `set_realtime`
+static REALTIME_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+struct Cmos;
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        let mut index = Port::<u8>::new(CMOS_INDEX);
+
+        unsafe {
+            index.write(NMI_DISABLE | register);
+            let value = Port::new(CMOS_DATA).read();
+            // Unmask NMIs again. Status register D is safe to leave selected.
+            index.write(STATUS_D);
+            value
+        }
+    }
+
+    fn updating(&mut self) -> bool {
+        self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Raw register values, which may be BCD or 12 hour depending on status register B
+    fn read_raw(&mut self) -> [u8; 6] {
+        while self.updating() {
+            core::hint::spin_loop();
+        }
+
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| self.read(register))
+    }
+}
+
+/// Wall-clock time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix(&self) -> i64 {
+        // Count from March so the leap day is the last day of the year
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // 719468 days from 0000-03-01 to 1970-01-01
+        let days = era * 146097 + day_of_era - 719468;
+
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// Decodes register values in the format described by status register B
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+
+    let pm = hour & HOUR_PM != 0;
+    let convert = |value: u8| if status_b & BINARY_MODE != 0 { value } else { from_bcd(value) };
+
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & HOUR_FORMAT_24 == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    DateTime {
+        // Without the ACPI century register, assume this century
+        year: 2000 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Reads the time from the CMOS RTC, which is assumed to be kept in UTC
+pub fn read_rtc() -> DateTime {
+    let mut cmos = CMOS.lock();
+
+    // The RTC may tick over between reading two registers, so read until two reads agree
+    let mut raw = cmos.read_raw();
+    loop {
+        let again = cmos.read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = cmos.read(STATUS_B);
+    decode(raw, status_b)
+}
+
+fn read_tsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack)) };
+    ((high as u64) << 32) | low as u64
+}
+
+/// Counts TSC ticks while PIT channel 2 counts down `CALIBRATION_MS`
+fn measure_tsc_frequency() -> u64 {
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+
+    unsafe {
+        // Hold the channel with the gate low while it is programmed, and keep the speaker off
+        let control = gate.read();
+        gate.write(control & !0b11);
+
+        Port::<u8>::new(PIT_COMMAND).write(PIT_ONE_SHOT);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = read_tsc();
+        gate.write((control & !0b10) | 0b01);
+        while gate.read() & PIT_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read_tsc();
+
+        gate.write(control);
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+/// Seeds the realtime clock from the RTC and measures the TSC, which keeps it running from there
+pub fn init() {
+    let frequency = measure_tsc_frequency();
+    let now = read_rtc();
+
+    BOOT_TSC.store(read_tsc(), Ordering::Relaxed);
+    BOOT_TIME.store(now.to_unix(), Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+
+    log::info!("RTC time is {} ({}), TSC runs at {} MHz", now, now.to_unix(), frequency / 1_000_000);
+}
+
+/// Seconds since the RTC was read in `init`
+fn uptime() -> i64 {
+    let elapsed = read_tsc().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed));
+    elapsed.checked_div(TSC_FREQUENCY.load(Ordering::Relaxed)).unwrap_or(0) as i64
+}
+
+/// The realtime clock (`CLOCK_REALTIME`), in seconds since the Unix epoch
+#[allow(dead_code, reason = "for clock_gettime, once there are syscalls")]
+pub fn realtime() -> i64 {
+    BOOT_TIME.load(Ordering::Relaxed) + uptime() + REALTIME_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Sets the realtime clock to `seconds` since the Unix epoch, for `settimeofday` and
+/// `clock_settime`. The RTC itself isn't changed.
+#[allow(dead_code, reason = "for settimeofday and clock_settime, once there are syscalls")]
+pub fn set_realtime(seconds: i64) {
+    REALTIME_OFFSET.store(seconds - BOOT_TIME.load(Ordering::Relaxed) - uptime(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn converts_to_unix_time() {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.to_unix(), 0);
+
+        let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+        assert_eq!(leap_day.to_unix(), 1_709_210_096);
+    }
+
+    #[test_case]
+    fn decodes_bcd_and_12_hour_registers() {
+        // 12:05:09 AM on 2024-12-31, BCD with 12 hour clock
+        let time = decode([0x09, 0x05, 0x12, 0x31, 0x12, 0x24], 0);
+        assert_eq!(time, DateTime { year: 2024, month: 12, day: 31, hour: 0, minute: 5, second: 9 });
+
+        // 11 PM in binary mode
+        let time = decode([0, 0, HOUR_PM | 11, 1, 1, 24], BINARY_MODE);
+        assert_eq!(time.hour, 23);
+    }
+}
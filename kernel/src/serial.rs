@@ -0,0 +1,136 @@
+use core::fmt;
+use core::fmt::Write;
+use crate::sync::IrqSpinlock;
+use x86_64::instructions::port::Port;
+use log::Level;
+use crate::klog::Sink;
+
+pub const COM1: u16 = 0x3f8;
+
+/// COM1, or `None` if there is no UART there (or `init` hasn't run yet)
+pub static SERIAL: IrqSpinlock<Option<SerialPort>> = IrqSpinlock::new(None);
+
+// Register offsets from the base port
+const DATA: u16 = 0; // Divisor latch low byte while DLAB is set
+const INTERRUPT_ENABLE: u16 = 1; // Divisor latch high byte while DLAB is set
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+#[cfg(feature = "gdb-stub")]
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Driver for a 16550 compatible UART, polling the line status register
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// # Safety
+    /// `base` must be the I/O port base of a 16550 compatible UART, and nothing else may use it.
+    pub const unsafe fn new(base: u16) -> Self {
+        SerialPort { base }
+    }
+
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Sets up 38400 baud 8N1 with FIFOs, returning false if the UART fails a loopback test
+    pub fn init(&mut self) -> bool {
+        unsafe {
+            self.register(INTERRUPT_ENABLE).write(0x00);
+            self.register(LINE_CONTROL).write(0x80); // Set DLAB to program the baud rate divisor
+            self.register(DATA).write(0x03); // 115200 / 3 = 38400 baud
+            self.register(INTERRUPT_ENABLE).write(0x00);
+            self.register(LINE_CONTROL).write(0x03); // 8 data bits, no parity, one stop bit
+            self.register(FIFO_CONTROL).write(0xc7); // Enable and clear FIFOs, 14 byte threshold
+            self.register(MODEM_CONTROL).write(0x1e); // Loopback mode to test the chip
+
+            self.register(DATA).write(0xae);
+            if self.register(DATA).read() != 0xae {
+                return false;
+            }
+
+            self.register(MODEM_CONTROL).write(0x0f); // Normal operation with DTR, RTS, OUT1, OUT2
+        }
+
+        true
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.register(LINE_STATUS).read() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+
+            self.register(DATA).write(byte);
+        }
+    }
+
+    /// Returns the next received byte, if there is one. Only the GDB stub reads from COM1.
+    #[cfg(feature = "gdb-stub")]
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.register(LINE_STATUS).read() & LINE_STATUS_DATA_READY != 0 {
+                Some(self.register(DATA).read())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.as_bytes() {
+            // Terminals expect CRLF line endings
+            if *byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(*byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends kernel log messages to COM1
+// Not registered with the GDB stub, which needs COM1 to itself
+#[cfg_attr(feature = "gdb-stub", allow(dead_code, reason = "COM1 carries the GDB remote protocol instead"))]
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, _level: Level, line: &str) {
+        if let Some(serial) = SERIAL.lock().as_mut() {
+            let _ = serial.write_str(line);
+        }
+    }
+}
+
+/// Probes and initialises COM1, making it available through `SERIAL`. With the GDB stub, COM1
+/// carries the remote protocol, so `SERIAL` is left empty and nothing else writes to it.
+pub fn init() {
+    let mut port = unsafe { SerialPort::new(COM1) };
+
+    if port.init() && !cfg!(feature = "gdb-stub") {
+        *SERIAL.lock() = Some(port);
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        if let Some(serial) = $crate::serial::SERIAL.lock().as_mut() {
+            let _ = <$crate::serial::SerialPort as core::fmt::Write>::write_fmt(serial, format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,184 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts;
+use crate::interrupts::in_interrupt;
+
+/// How many `IrqSpinlock` guards are alive on this CPU. There is only one CPU until SMP bringup,
+/// so these don't need to be per-CPU variables yet.
+static HELD: AtomicUsize = AtomicUsize::new(0);
+/// Whether interrupts were enabled before the outermost guard disabled them
+static INTERRUPTS_WERE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Disables interrupts, remembering whether they were on if no other guard is held
+fn push_interrupts_off() {
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+
+    if HELD.fetch_add(1, Ordering::Relaxed) == 0 {
+        INTERRUPTS_WERE_ENABLED.store(were_enabled, Ordering::Relaxed);
+    }
+}
+
+/// Turns interrupts back on once the last guard is released, if they were on to begin with.
+/// Guards can be dropped in any order, since only the outermost state is restored.
+fn pop_interrupts_off() {
+    if HELD.fetch_sub(1, Ordering::Relaxed) == 1 && INTERRUPTS_WERE_ENABLED.load(Ordering::Relaxed) {
+        interrupts::enable();
+    }
+}
+
+/// Spinlock that keeps interrupts disabled while it is held, so an interrupt handler can never
+/// spin on a lock held by the code it interrupted. Use this for anything an interrupt handler
+/// (or a panic in one) might touch. Handlers must use `try_lock`, since the code they interrupted
+/// may hold the lock.
+pub struct IrqSpinlock<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    #[cfg(feature = "lock-validator")]
+    lock: usize,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinlock {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        // An exception can be raised inside a critical section, so a handler that waits for the
+        // lock could spin forever on the code it interrupted
+        debug_assert!(!in_interrupt(), "IrqSpinlock locked in an interrupt handler, use try_lock");
+
+        push_interrupts_off();
+
+        #[cfg(feature = "lock-validator")]
+        crate::lockdep::acquire(self.id());
+
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            #[cfg(feature = "lock-validator")]
+            lock: self.id(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        push_interrupts_off();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lock-validator")]
+                crate::lockdep::acquired(self.id());
+
+                Some(IrqSpinlockGuard {
+                    guard: ManuallyDrop::new(guard),
+                    #[cfg(feature = "lock-validator")]
+                    lock: self.id(),
+                })
+            }
+            None => {
+                pop_interrupts_off();
+                None
+            }
+        }
+    }
+
+    /// Identifies the lock to the lock validator
+    #[cfg(feature = "lock-validator")]
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before an interrupt can come in and want it
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        #[cfg(feature = "lock-validator")]
+        crate::lockdep::release(self.lock);
+
+        pop_interrupts_off();
+    }
+}
+
+/// Value initialised once, with interrupts disabled so an interrupt handler can't find it half
+/// initialised and spin waiting for code it interrupted
+pub struct IrqOnceCell<T> {
+    inner: Once<T>,
+}
+
+impl<T> IrqOnceCell<T> {
+    pub const fn new() -> Self {
+        IrqOnceCell { inner: Once::new() }
+    }
+
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        interrupts::without_interrupts(|| self.inner.call_once(f))
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.inner.get()
+    }
+}
+
+impl<T> Default for IrqOnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn guard_restores_interrupt_state() {
+        let lock = IrqSpinlock::new(0);
+        let before = interrupts::are_enabled();
+
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+            assert!(lock.try_lock().is_none());
+        }
+
+        assert_eq!(interrupts::are_enabled(), before);
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+
+    #[test_case]
+    fn guards_dropped_out_of_order_keep_interrupts_off() {
+        let (first, second) = (IrqSpinlock::new(()), IrqSpinlock::new(()));
+        let before = interrupts::are_enabled();
+
+        let first_guard = first.lock();
+        let second_guard = second.lock();
+
+        drop(first_guard);
+        assert!(!interrupts::are_enabled());
+
+        drop(second_guard);
+        assert_eq!(interrupts::are_enabled(), before);
+    }
+}
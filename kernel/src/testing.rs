@@ -0,0 +1,27 @@
+use core::fmt::Write;
+use crate::{debug_print, debug_println};
+use crate::qemu::{exit_qemu, QemuExitCode};
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let _ = debug_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        debug_println!("[ok]");
+    }
+}
+
+/// Runs every `#[test_case]` and exits QEMU. A failing test panics, and the panic handler exits
+/// QEMU with `QemuExitCode::Failed`.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    debug_println!("Running {} tests", tests.len());
+
+    for test in tests {
+        test.run();
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
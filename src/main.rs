@@ -1,14 +1,179 @@
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// What the kernel writes to the isa-debug-exit device on success, QEMU exits with
+/// `(code << 1) | 1`
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_FAILURE: i32 = (0x11 << 1) | 1;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_LOG: &str = "debugcon.log";
+
+/// Exit status when QEMU had to be killed
+const TIMED_OUT: i32 = 124;
+
+/// Runs without a display and stops QEMU if the kernel hasn't exited after `timeout`
+struct Headless {
+    timeout: Duration,
+}
+
+/// Machine settings from the command line, anything unset is left to QEMU's defaults
+#[derive(Default)]
+struct Machine {
+    /// Passed to `-m`, such as `512M`
+    memory: Option<String>,
+    cpus: Option<u32>,
+    /// Raw images attached as virtio-blk devices
+    drives: Vec<PathBuf>,
+    kvm: bool,
+    /// Where COM1 goes, passed to `-serial` as is, such as `stdio`, `file:<path>` or
+    /// `tcp::1234,server`
+    serial: Option<String>,
+}
+
+impl Machine {
+    fn apply(&self, cmd: &mut Command) {
+        if let Some(memory) = &self.memory {
+            cmd.arg("-m").arg(memory);
+        }
+        if let Some(cpus) = self.cpus {
+            cmd.arg("-smp").arg(cpus.to_string());
+        }
+        for drive in &self.drives {
+            cmd.arg("-drive").arg(format!("format=raw,if=virtio,file={}", drive.display()));
+        }
+        if self.kvm {
+            cmd.arg("-enable-kvm").arg("-cpu").arg("host");
+        }
+        if let Some(serial) = &self.serial {
+            cmd.arg("-serial").arg(serial);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: benchix [test [--timeout <seconds>] [--log <file>]] [options]");
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("    --mem <size>       memory size, such as 512M");
+    eprintln!("    --smp <cpus>       number of CPUs");
+    eprintln!("    --drive <image>    attach a raw disk image as virtio-blk, may be repeated");
+    eprintln!("    --kvm              use KVM acceleration");
+    eprintln!("    --serial <dev>     QEMU character device for COM1, such as stdio or file:<path>");
+    exit(2);
+}
+
 fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+
+    // `cargo test` on the kernel runs us with the test binary to boot
+    if let Some(kernel) = args.next_if(|arg| arg != "test" && !arg.starts_with("--")) {
+        let uefi_path = create_disk_image(Path::new(&kernel));
+        let status = run_qemu(&uefi_path, &Machine::default(), "stdio", Some(Headless { timeout: DEFAULT_TIMEOUT }));
+
+        exit(report(status, None));
+    }
+
+    // In test mode the kernel runs headless and exits QEMU once it has booted or panicked
+    let test = args.next_if_eq("test").is_some();
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut log = PathBuf::from(DEFAULT_LOG);
+    let mut machine = Machine::default();
+
+    while let Some(arg) = args.next() {
+        if arg == "--kvm" {
+            machine.kvm = true;
+            continue;
+        }
+
+        let Some(value) = args.next() else {
+            usage();
+        };
+
+        match arg.as_str() {
+            "--mem" => machine.memory = Some(value),
+            "--smp" => machine.cpus = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--drive" => machine.drives.push(PathBuf::from(value)),
+            "--serial" => machine.serial = Some(value),
+            "--timeout" if test => timeout = Duration::from_secs(value.parse().unwrap_or_else(|_| usage())),
+            "--log" if test => log = PathBuf::from(value),
+            _ => usage(),
+        }
+    }
+
     // read env variables that were set in build script
-    let uefi_path = env!("UEFI_PATH");
+    let uefi_path = Path::new(env!("UEFI_PATH"));
+
+    if test {
+        let status = run_qemu(uefi_path, &machine, &format!("file:{}", log.display()), Some(Headless { timeout }));
+        exit(report(status, Some(&log)));
+    }
 
     println!("UEFI Path s{:?}", uefi_path);
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    cmd.arg("-debugcon").arg("stdio");
+    run_qemu(uefi_path, &machine, "stdio", None);
+}
+
+fn create_disk_image(kernel: &Path) -> PathBuf {
+    let uefi_path = kernel.with_extension("img");
+    bootloader::UefiBoot::new(kernel).create_disk_image(&uefi_path).unwrap();
+    uefi_path
+}
+
+/// Turns QEMU's exit status from a headless run into ours, `None` meaning it timed out
+fn report(status: Option<i32>, log: Option<&Path>) -> i32 {
+    let log = log.map(|log| format!(", see {}", log.display())).unwrap_or_default();
+
+    match status {
+        Some(QEMU_SUCCESS) => 0,
+        Some(QEMU_FAILURE) => {
+            eprintln!("Kernel failed{}", log);
+            1
+        }
+        Some(status) => {
+            eprintln!("QEMU exited with {}{}", status, log);
+            1
+        }
+        None => {
+            eprintln!("Timed out waiting for the kernel to exit{}", log);
+            TIMED_OUT
+        }
+    }
+}
+
+/// Boots `uefi_path` with debugcon sent to `debugcon` (a QEMU character device such as `stdio`)
+/// and returns QEMU's exit status. Headless runs exit through the isa-debug-exit device, and
+/// return `None` if they time out.
+fn run_qemu(uefi_path: &Path, machine: &Machine, debugcon: &str, headless: Option<Headless>) -> Option<i32> {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    cmd.arg("-debugcon").arg(debugcon);
     cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
-    cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
+    cmd.arg("-drive").arg(format!("format=raw,file={}", uefi_path.display()));
+    machine.apply(&mut cmd);
+
+    let Some(headless) = headless else {
+        let mut child = cmd.spawn().unwrap();
+        return Some(child.wait().unwrap().code().unwrap_or(-1));
+    };
+
+    cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    cmd.arg("-display").arg("none");
+    // Tells the kernel to exit QEMU when it is done, see kernel/src/qemu.rs
+    cmd.arg("-fw_cfg").arg("name=opt/benchix/ci,string=1");
 
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
-}
\ No newline at end of file
+    let deadline = Instant::now() + headless.timeout;
+
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status.code().unwrap_or(-1));
+        }
+        sleep(Duration::from_millis(100));
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+    None
+}